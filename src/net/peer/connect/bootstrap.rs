@@ -0,0 +1,283 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use net::peer;
+use net::peer::connect::connect::SingleConnectionError;
+use net::peer::connect::handshake_message::{ConnectRequest, HandshakeMessage};
+use priv_prelude::*;
+use serde_json;
+use std::fs::File;
+use std::io::{Read as StdRead, Write as StdWrite};
+use std::path::PathBuf;
+
+/// Cache entries are dropped once they've failed this many consecutive connection attempts.
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+/// Bound on how many addresses we'll remember, so a node that's churned through many networks
+/// doesn't grow its cache file forever.
+const MAX_CACHE_SIZE: usize = 1500;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum BootstrapError {
+        Io(e: io::Error) {
+            description("io error bootstrapping onto the network")
+            display("io error bootstrapping onto the network: {}", e)
+            cause(e)
+        }
+        AllAttemptsFailed(v: Vec<SingleConnectionError>) {
+            description("all attempts to bootstrap onto the network failed")
+            display("all {} attempts to bootstrap onto the network failed: {:?}", v.len(), v)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    addr: SocketAddr,
+    consecutive_failures: u32,
+}
+
+/// A disk-backed, most-recently-successful-first list of peer addresses we've directly
+/// connected to before, keyed by network `name_hash`. Modeled on qp2p's `bootstrap` module: it
+/// lets a node rejoin a network it's already seen without running rendezvous/discovery again.
+pub struct BootstrapCache {
+    path: PathBuf,
+    entries: RefCell<Vec<CacheEntry>>,
+}
+
+impl BootstrapCache {
+    /// Loads the cache for `name_hash` from `cache_dir`, or starts empty if there's nothing on
+    /// disk yet (first run, or a network we've never bootstrapped onto before).
+    pub fn new(cache_dir: &Path, name_hash: NameHash) -> BootstrapCache {
+        let path = cache_dir.join(format!("bootstrap_cache_{:x}.json", name_hash));
+        let entries = Self::read_from_disk(&path).unwrap_or_else(Vec::new);
+        BootstrapCache {
+            path: path,
+            entries: RefCell::new(entries),
+        }
+    }
+
+    fn read_from_disk(path: &Path) -> Option<Vec<CacheEntry>> {
+        let mut file = File::open(path).ok()?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    fn write_to_disk(&self) {
+        let contents = match serde_json::to_string(&*self.entries.borrow()) {
+            Ok(contents) => contents,
+            Err(_) => return,
+        };
+        if let Ok(mut file) = File::create(&self.path) {
+            let _ = file.write_all(contents.as_bytes());
+        }
+    }
+
+    /// Addresses we've succeeded against before, most-recently-successful first, followed by any
+    /// of `hard_coded_contacts` we don't already have cached.
+    pub fn ordered_addrs(&self, hard_coded_contacts: &[SocketAddr]) -> Vec<SocketAddr> {
+        let entries = self.entries.borrow();
+        let mut addrs: Vec<SocketAddr> = entries.iter().map(|entry| entry.addr).collect();
+        for contact in hard_coded_contacts {
+            if !addrs.contains(contact) {
+                addrs.push(*contact);
+            }
+        }
+        addrs
+    }
+
+    /// Moves `addr` to the front of the cache and clears its failure count. Called by `connect()`
+    /// with the peer address of whichever connection wins, and by `bootstrap()` for every address
+    /// whose `Connect` handshake succeeds.
+    pub fn record_success(&self, addr: SocketAddr) {
+        let mut entries = self.entries.borrow_mut();
+        entries.retain(|entry| entry.addr != addr);
+        entries.insert(
+            0,
+            CacheEntry {
+                addr: addr,
+                consecutive_failures: 0,
+            },
+        );
+        entries.truncate(MAX_CACHE_SIZE);
+        drop(entries);
+        self.write_to_disk();
+    }
+
+    /// Bumps `addr`'s failure count, evicting it once it's failed `MAX_CONSECUTIVE_FAILURES`
+    /// times in a row.
+    pub fn record_failure(&self, addr: SocketAddr) {
+        let mut entries = self.entries.borrow_mut();
+        if let Some(entry) = entries.iter_mut().find(|entry| entry.addr == addr) {
+            entry.consecutive_failures += 1;
+        }
+        entries.retain(|entry| entry.consecutive_failures < MAX_CONSECUTIVE_FAILURES);
+        drop(entries);
+        self.write_to_disk();
+    }
+}
+
+/// Tries every address in the bootstrap cache (most-recently-successful first), then any
+/// `hard_coded_contacts` not already cached, racing TCP connects the same way `direct_connections`
+/// does in `connect()`, and returns the first peer we complete a handshake with.
+pub fn bootstrap<UID: Uid>(
+    handle: &Handle,
+    name_hash: NameHash,
+    our_uid: UID,
+    hard_coded_contacts: Vec<SocketAddr>,
+    cache: Rc<BootstrapCache>,
+) -> BoxFuture<Peer<UID>, BootstrapError> {
+    let addrs = cache.ordered_addrs(&hard_coded_contacts);
+    let our_connect_request = ConnectRequest {
+        uid: our_uid,
+        name_hash: name_hash,
+    };
+
+    let handle1 = handle.clone();
+    let handle2 = handle.clone();
+    let attempts = stream::futures_unordered(addrs.into_iter().map(move |addr| {
+        let cache = cache.clone();
+        let our_connect_request = our_connect_request.clone();
+        let handle = handle1.clone();
+        TcpStream::connect(&addr, &handle)
+            .map_err(SingleConnectionError::Io)
+            .and_then(move |stream| {
+                let peer_addr = unwrap!(stream.peer_addr());
+                let socket = Socket::wrap_tcp(&handle, stream, peer_addr);
+                socket
+                    .send((0, HandshakeMessage::Connect(our_connect_request.clone())))
+                    .map_err(SingleConnectionError::Socket)
+            })
+            .and_then(|socket| {
+                socket.into_future().map_err(
+                    |(err, _socket)| SingleConnectionError::Socket(err),
+                )
+            })
+            .and_then(move |(msg_opt, socket)| match msg_opt {
+                None => Err(SingleConnectionError::ConnectionDropped),
+                Some(HandshakeMessage::Connect(connect_request)) => {
+                    if connect_request.name_hash != name_hash {
+                        return Err(SingleConnectionError::InvalidNameHash(connect_request.name_hash));
+                    }
+                    Ok((socket, connect_request.uid))
+                }
+                Some(_msg) => Err(SingleConnectionError::UnexpectedMessage),
+            })
+            .then(move |result| {
+                match result {
+                    Ok(_) => cache.record_success(addr),
+                    Err(_) => cache.record_failure(addr),
+                }
+                result
+            })
+    }).collect::<Vec<_>>());
+
+    attempts
+        .first_ok()
+        .map_err(BootstrapError::AllAttemptsFailed)
+        .and_then(move |(socket, their_uid)| {
+            peer::from_handshaken_socket(&handle2, socket, their_uid, CrustUser::Node)
+                .map_err(BootstrapError::Io)
+        })
+        .into_boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn addr(last_octet: u8) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, last_octet)), 12345)
+    }
+
+    fn cache_at(path: PathBuf) -> BootstrapCache {
+        BootstrapCache {
+            path: path,
+            entries: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn scratch_cache() -> BootstrapCache {
+        // A path under a directory that doesn't exist: `write_to_disk` fails silently, which is
+        // exactly what every other test here relies on - we're only exercising the in-memory
+        // ordering/eviction logic, not the serde round-trip.
+        cache_at(PathBuf::from("/nonexistent/bootstrap_cache_test.json"))
+    }
+
+    #[test]
+    fn ordered_addrs_puts_the_most_recently_successful_address_first() {
+        let cache = scratch_cache();
+        cache.record_success(addr(1));
+        cache.record_success(addr(2));
+        assert_eq!(cache.ordered_addrs(&[]), vec![addr(2), addr(1)]);
+    }
+
+    #[test]
+    fn ordered_addrs_appends_hard_coded_contacts_not_already_cached() {
+        let cache = scratch_cache();
+        cache.record_success(addr(1));
+        assert_eq!(
+            cache.ordered_addrs(&[addr(1), addr(2)]),
+            vec![addr(1), addr(2)]
+        );
+    }
+
+    #[test]
+    fn record_success_moves_an_existing_entry_to_the_front_instead_of_duplicating_it() {
+        let cache = scratch_cache();
+        cache.record_success(addr(1));
+        cache.record_success(addr(2));
+        cache.record_success(addr(1));
+        assert_eq!(cache.ordered_addrs(&[]), vec![addr(1), addr(2)]);
+    }
+
+    #[test]
+    fn record_success_truncates_the_cache_at_max_cache_size() {
+        let cache = scratch_cache();
+        for i in 0..(MAX_CACHE_SIZE + 10) {
+            let ip = Ipv4Addr::new(127, 0, (i / 250) as u8, (i % 250) as u8);
+            cache.record_success(SocketAddr::new(IpAddr::V4(ip), 12345));
+        }
+        assert_eq!(cache.entries.borrow().len(), MAX_CACHE_SIZE);
+    }
+
+    #[test]
+    fn record_failure_evicts_an_entry_after_max_consecutive_failures() {
+        let cache = scratch_cache();
+        cache.record_success(addr(1));
+        for _ in 0..(MAX_CONSECUTIVE_FAILURES - 1) {
+            cache.record_failure(addr(1));
+            assert_eq!(cache.ordered_addrs(&[]), vec![addr(1)]);
+        }
+        cache.record_failure(addr(1));
+        assert!(cache.ordered_addrs(&[]).is_empty());
+    }
+
+    #[test]
+    fn record_success_after_failures_resets_the_consecutive_failure_count() {
+        let cache = scratch_cache();
+        cache.record_success(addr(1));
+        for _ in 0..(MAX_CONSECUTIVE_FAILURES - 1) {
+            cache.record_failure(addr(1));
+        }
+        cache.record_success(addr(1));
+        let entries = cache.entries.borrow();
+        assert_eq!(entries[0].consecutive_failures, 0);
+    }
+}
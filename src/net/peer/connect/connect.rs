@@ -19,10 +19,16 @@ use future_utils::bi_channel::UnboundedBiChannel;
 use futures::sync::mpsc::UnboundedReceiver;
 use futures::sync::oneshot;
 use net::peer;
+use net::peer::connect::bootstrap::BootstrapCache;
 use net::peer::connect::demux::ConnectMessage;
 use net::peer::connect::handshake_message::{ConnectRequest, HandshakeMessage};
+use net::peer::connect::limiter::{ConnectionDecision, ConnectionLimiter, PublicHandshakeInfo};
+use net::peer::connect::metrics::{ConnectKind, ConnectMetrics};
+use net::peer::connect::rate_limiter::RateLimiter;
+use net::peer::connect::relay::relay_connection;
 use p2p::{TcpStreamExt, TcpRendezvousConnectError};
 use priv_prelude::*;
+use std::time::Instant;
 
 const TIMEOUT_SEC: u64 = 60;
 
@@ -91,6 +97,16 @@ quick_error! {
             display("p2p::rendezvous_connect failed: {}", e)
             cause(e)
         }
+        RelayUnavailable {
+            description("no relay nodes were configured/reachable to fall back to")
+        }
+        Rejected(reason: String) {
+            description("incoming handshake was rejected by the connection limiter")
+            display("incoming handshake was rejected by the connection limiter: {}", reason)
+        }
+        RateLimited {
+            description("incoming handshake was dropped by the per-ip rate limiter")
+        }
     }
 }
 
@@ -102,15 +118,19 @@ pub fn connect<UID: Uid>(
     name_hash: NameHash,
     our_info: PrivConnectionInfo<UID>,
     their_info: PubConnectionInfo<UID>,
-    _config: ConfigFile,
+    config: ConfigFile,
+    limiter: Rc<ConnectionLimiter<UID>>,
+    current_peer_count: Rc<Cell<usize>>,
+    bootstrap_cache: Rc<BootstrapCache>,
+    metrics: ConnectMetrics,
+    rate_limiter: Rc<RateLimiter>,
     peer_rx: UnboundedReceiver<ConnectMessage<UID>>,
 ) -> BoxFuture<Peer<UID>, ConnectError> {
     if our_info.id == their_info.id {
         return future::result(Err(ConnectError::RequestedConnectToSelf)).into_boxed();
     }
 
-    // TODO(povilas): respect `whitelisted_node_ips` config
-
+    let start_time = Instant::now();
     let their_id = their_info.id;
     let our_connect_request = ConnectRequest {
         uid: our_info.id,
@@ -119,11 +139,29 @@ pub fn connect<UID: Uid>(
 
     let direct_incoming = {
         let our_connect_request = our_connect_request.clone();
+        let current_peer_count = current_peer_count.clone();
         peer_rx
         .map_err(|()| unreachable!())
         .infallible::<SingleConnectionError>()
         .and_then(move |(socket, connect_request)| {
+            let peer_addr = socket.peer_addr().map_err(SingleConnectionError::Socket)?;
+            if !rate_limiter.try_acquire(peer_addr.ip()) {
+                return Err(SingleConnectionError::RateLimited);
+            }
             validate_connect_request(their_id, name_hash, &connect_request)?;
+            let info = PublicHandshakeInfo {
+                uid: connect_request.uid,
+                addr: peer_addr,
+            };
+            match limiter.decide(&info, current_peer_count.get()) {
+                ConnectionDecision::Accept => (),
+                ConnectionDecision::Reject(reason) => {
+                    return Err(SingleConnectionError::Rejected(reason));
+                }
+                ConnectionDecision::DropSilently => {
+                    return Err(SingleConnectionError::ConnectionDropped);
+                }
+            }
             Ok({
                 socket
                 .send((0, HandshakeMessage::Connect(our_connect_request.clone())))
@@ -135,6 +173,7 @@ pub fn connect<UID: Uid>(
     };
 
     let their_direct = their_info.for_direct;
+    metrics.record_attempt(name_hash, ConnectKind::Direct);
     let direct_connections = stream::futures_unordered(
         their_direct
             .into_iter()
@@ -142,6 +181,7 @@ pub fn connect<UID: Uid>(
             .collect::<Vec<_>>(),
     ).map_err(SingleConnectionError::Io);
 
+    metrics.record_attempt(name_hash, ConnectKind::Rendezvous);
     let conn_info = Bytes::from(their_info.p2p_conn_info);
     let conn_rx = our_info.connection_rx;
     let p2p_connection = our_info
@@ -154,38 +194,71 @@ pub fn connect<UID: Uid>(
                 .and_then(|res| res)
         });
 
+    let relay_connect_request = our_connect_request.clone();
     let handle1 = handle.clone();
     let handle2 = handle.clone();
     let all_connections = direct_connections
-        .select(p2p_connection.into_stream())
-        .map(move |stream| {
+        .map(|stream| (ConnectKind::Direct, stream))
+        .select(p2p_connection.into_stream().map(|stream| {
+            (ConnectKind::Rendezvous, stream)
+        }))
+        .map(move |(kind, stream)| {
             let peer_addr = unwrap!(stream.peer_addr());
-            Socket::wrap_tcp(&handle1, stream, peer_addr)
+            (kind, Socket::wrap_tcp(&handle1, stream, peer_addr))
         })
-        .and_then(move |socket| {
+        .and_then(move |(kind, socket)| {
             socket
                 .send((0, HandshakeMessage::Connect(our_connect_request.clone())))
+                .map(move |socket| (kind, socket))
                 .map_err(SingleConnectionError::Socket)
         })
-        .and_then(move |socket| {
-            socket.into_future().map_err(|(err, _socket)| {
-                SingleConnectionError::Socket(err)
-            })
+        .and_then(move |(kind, socket)| {
+            socket
+                .into_future()
+                .map(move |(msg_opt, socket)| (kind, msg_opt, socket))
+                .map_err(|(err, _socket)| SingleConnectionError::Socket(err))
         })
-        .and_then(move |(msg_opt, socket)| match msg_opt {
+        .and_then(move |(kind, msg_opt, socket)| match msg_opt {
             None => Err(SingleConnectionError::ConnectionDropped),
             Some(HandshakeMessage::Connect(connect_request)) => {
                 validate_connect_request(their_id, name_hash, &connect_request)?;
-                Ok((socket, connect_request.uid))
+                Ok((socket, connect_request.uid, kind))
             }
             Some(_msg) => Err(SingleConnectionError::UnexpectedMessage),
         });
 
+    // Symmetric-NAT peers can't always be reached directly or rendezvous-punched; dial every
+    // relay listed in our config and let whichever pairs us up first forward bytes between us
+    // and `their_id`.
+    let relay_addrs = config.read().relay_servers.clone();
+    metrics.record_attempt(name_hash, ConnectKind::Relay);
+    let relayed_connection = relay_connection(
+        handle,
+        name_hash,
+        relay_connect_request,
+        their_id,
+        relay_addrs,
+    ).map(|(socket, uid)| (socket, uid, ConnectKind::Relay));
+
+    let direct_incoming = direct_incoming.map(|(socket, uid)| (socket, uid, ConnectKind::Incoming));
+
+    let metrics_on_failure = metrics.clone();
     all_connections
         .select(direct_incoming)
+        .select(relayed_connection)
         .first_ok()
-        .map_err(ConnectError::AllConnectionsFailed)
-        .and_then(move |(socket, their_uid)| {
+        .map_err(move |v| {
+            for err in &v {
+                metrics_on_failure.record_failure(name_hash, err);
+            }
+            ConnectError::AllConnectionsFailed(v)
+        })
+        .and_then(move |(socket, their_uid, kind)| {
+            metrics.record_success(name_hash, kind);
+            metrics.record_time_to_connect(name_hash, start_time.elapsed());
+            if let Ok(peer_addr) = socket.peer_addr() {
+                bootstrap_cache.record_success(peer_addr);
+            }
             peer::from_handshaken_socket(&handle2, socket, their_uid, CrustUser::Node)
                 .map_err(ConnectError::Io)
         })
@@ -193,7 +266,7 @@ pub fn connect<UID: Uid>(
 }
 
 
-fn validate_connect_request<UID: Uid>(
+pub(crate) fn validate_connect_request<UID: Uid>(
     expected_uid: UID,
     our_name_hash: NameHash,
     connect_request: &ConnectRequest<UID>,
@@ -225,12 +298,19 @@ fn validate_connect_request<UID: Uid>(
 /// connection receiver
 pub fn start_rendezvous_connect(
     handle: &Handle,
+    name_hash: NameHash,
     rendezvous_relay: UnboundedBiChannel<Bytes>,
+    metrics: ConnectMetrics,
 ) -> oneshot::Receiver<Result<TcpStream, SingleConnectionError>> {
     let (conn_tx, conn_rx) = oneshot::channel();
     let start_conn = TcpStream::rendezvous_connect(rendezvous_relay, handle)
         .map_err(SingleConnectionError::RendezvousConnect)
-        .then(move |result| conn_tx.send(result))
+        .then(move |result| {
+            if let Err(ref err) = result {
+                metrics.record_failure(name_hash, err);
+            }
+            conn_tx.send(result)
+        })
         .or_else(|_send_error| Ok(()));
     handle.spawn(start_conn);
     conn_rx
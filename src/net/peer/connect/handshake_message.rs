@@ -0,0 +1,74 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use priv_prelude::*;
+
+/// Sent by a peer as the very first message of a direct or rendezvous connection attempt, so
+/// that the other side can confirm it's talking to who it expects, on the expected network.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConnectRequest<UID: Uid> {
+    pub uid: UID,
+    pub name_hash: NameHash,
+}
+
+/// The messages exchanged over a `Socket` while a connection is being established, whether it's
+/// a direct TCP connect, a p2p rendezvous connect, a rendezvous-server registration, or a
+/// relayed connection.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum HandshakeMessage<UID: Uid> {
+    /// Exchanged by both sides of a connection once a socket has been established, so each peer
+    /// can confirm the other's identity and network.
+    Connect(ConnectRequest<UID>),
+    /// Register our connection info with a rendezvous server under `namespace`, for `ttl_secs`.
+    Register {
+        namespace: String,
+        uid: UID,
+        name_hash: NameHash,
+        info: PubConnectionInfo<UID>,
+        ttl_secs: u64,
+    },
+    /// Ask a rendezvous server for the live registrations under `namespace`.
+    Discover {
+        namespace: String,
+        name_hash: NameHash,
+        cookie: Option<RendezvousCookie>,
+    },
+    /// A rendezvous server's answer to `Discover`. `cookie` is `Some` when there are more
+    /// results to page through with another `Discover`.
+    DiscoverResponse {
+        registrations: Vec<PubConnectionInfo<UID>>,
+        cookie: Option<RendezvousCookie>,
+    },
+    /// Withdraw a previous `Register` before its TTL expires.
+    Unregister {
+        namespace: String,
+        uid: UID,
+        name_hash: NameHash,
+    },
+    /// Sent to a relay node by each of two peers. The relay holds the first request until
+    /// `target_uid` sends its own asking to be relayed to `uid`, then pipes a byte stream
+    /// between them.
+    RelayRequest { uid: UID, target_uid: UID, name_hash: NameHash },
+    /// A chunk of the relayed byte stream, forwarded verbatim by the relay node between the two
+    /// peers it has matched up by uid pair.
+    RelayData(Vec<u8>),
+}
+
+/// Opaque pagination token returned alongside a `DiscoverResponse`. Callers just feed it back
+/// into their next `Discover` unchanged; they shouldn't inspect or construct one themselves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RendezvousCookie(pub usize);
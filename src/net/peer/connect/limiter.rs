@@ -0,0 +1,285 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use priv_prelude::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// `inbound_per_ip` counts are only meaningful within this trailing window; once it elapses the
+/// count for that IP resets. Without this the cap would be a one-time lifetime quota instead of
+/// a per-source rate, permanently locking out an IP (and every distinct peer behind it) the
+/// moment it's been seen `max_inbound_connections_per_ip` times.
+const INBOUND_WINDOW_SECS: u64 = 60;
+/// IPs that haven't attempted a handshake in this long are forgotten, so `inbound_per_ip` doesn't
+/// grow forever.
+const INBOUND_IDLE_EVICTION_SECS: u64 = 300;
+
+/// Everything about an incoming handshake that's known before we decide whether to accept it:
+/// who it claims to be and where it's coming from.
+#[derive(Clone, Debug)]
+pub struct PublicHandshakeInfo<UID: Uid> {
+    pub uid: UID,
+    pub addr: SocketAddr,
+}
+
+/// What to do with an incoming handshake, decided by a `ConnectionLimiter` before we even send
+/// our `Connect` reply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionDecision {
+    /// Carry on with the handshake as normal.
+    Accept,
+    /// Refuse the handshake, surfacing `SingleConnectionError::Rejected(reason)` to this side
+    /// (the remote peer just sees the socket close).
+    Reject(String),
+    /// Refuse the handshake without replying at all, as if we'd never seen it. Used for peers
+    /// we suspect are abusive rather than merely over quota.
+    DropSilently,
+}
+
+/// A callback routing logic above crust can plug in to make admission decisions using more than
+/// raw counts, e.g. preferring peers closer to us in XOR space over a flood of new ones.
+pub type AdmissionCallback<UID> = Rc<Fn(&PublicHandshakeInfo<UID>) -> ConnectionDecision>;
+
+struct InboundWindow {
+    count: usize,
+    window_start: Instant,
+}
+
+/// Enforces `ConfigFile`'s `whitelisted_node_ips`, `max_peers` and
+/// `max_inbound_connections_per_ip` on every incoming handshake, before our `Connect` reply is
+/// sent. Sits in front of `direct_incoming` in `connect()`.
+pub struct ConnectionLimiter<UID: Uid> {
+    config: ConfigFile,
+    inbound_per_ip: RefCell<HashMap<IpAddr, InboundWindow>>,
+    last_eviction: Cell<Instant>,
+    callback: Option<AdmissionCallback<UID>>,
+}
+
+impl<UID: Uid> ConnectionLimiter<UID> {
+    pub fn new(config: ConfigFile) -> ConnectionLimiter<UID> {
+        ConnectionLimiter {
+            config: config,
+            inbound_per_ip: RefCell::new(HashMap::new()),
+            last_eviction: Cell::new(Instant::now()),
+            callback: None,
+        }
+    }
+
+    /// Lets routing logic above crust veto/accept admissions using the peer's uid and address,
+    /// rather than just whitelist/count checks.
+    pub fn with_callback(config: ConfigFile, callback: AdmissionCallback<UID>) -> ConnectionLimiter<UID> {
+        ConnectionLimiter {
+            config: config,
+            inbound_per_ip: RefCell::new(HashMap::new()),
+            last_eviction: Cell::new(Instant::now()),
+            callback: Some(callback),
+        }
+    }
+
+    /// Decides whether to admit `info`, given that we currently hold `current_peer_count` peer
+    /// connections.
+    pub fn decide(&self, info: &PublicHandshakeInfo<UID>, current_peer_count: usize) -> ConnectionDecision {
+        let config = self.config.read();
+
+        if !is_whitelisted(&config.whitelisted_node_ips, info.addr.ip()) {
+            return ConnectionDecision::Reject(format!(
+                "{} is not in whitelisted_node_ips",
+                info.addr.ip()
+            ));
+        }
+
+        if current_peer_count >= config.max_peers {
+            return ConnectionDecision::Reject(format!(
+                "already have {} peers (max_peers == {})",
+                current_peer_count, config.max_peers
+            ));
+        }
+
+        {
+            let now = Instant::now();
+            self.evict_idle(now);
+
+            let mut inbound_per_ip = self.inbound_per_ip.borrow_mut();
+            let window = inbound_per_ip.entry(info.addr.ip()).or_insert_with(|| {
+                InboundWindow {
+                    count: 0,
+                    window_start: now,
+                }
+            });
+            let (count, window_start, allowed) = apply_inbound_window(
+                window.count,
+                window.window_start,
+                now,
+                config.max_inbound_connections_per_ip,
+            );
+            window.count = count;
+            window.window_start = window_start;
+            if !allowed {
+                return ConnectionDecision::Reject(format!(
+                    "{} is over max_inbound_connections_per_ip == {}",
+                    info.addr.ip(),
+                    config.max_inbound_connections_per_ip
+                ));
+            }
+        }
+
+        match self.callback {
+            Some(ref callback) => callback(info),
+            None => ConnectionDecision::Accept,
+        }
+    }
+
+    /// Forgets IPs that haven't attempted a handshake in `INBOUND_IDLE_EVICTION_SECS`, so a
+    /// burst from a since-gone-quiet IP doesn't sit in memory forever.
+    fn evict_idle(&self, now: Instant) {
+        if now.duration_since(self.last_eviction.get()) < Duration::from_secs(INBOUND_IDLE_EVICTION_SECS) {
+            return;
+        }
+        self.last_eviction.set(now);
+        self.inbound_per_ip.borrow_mut().retain(|_addr, window| {
+            is_fresh(window.window_start, now)
+        });
+    }
+}
+
+/// Whether `ip` is allowed through `whitelisted_node_ips` - an empty list means no whitelist is
+/// configured, so everything is allowed.
+fn is_whitelisted(whitelisted_node_ips: &[IpAddr], ip: IpAddr) -> bool {
+    whitelisted_node_ips.is_empty() || whitelisted_node_ips.contains(&ip)
+}
+
+/// Resets `count` to 0 if `window_start` is more than `INBOUND_WINDOW_SECS` in the past, then
+/// checks the (possibly reset) count against `max`. Returns the window's new `count`,
+/// `window_start`, and whether this handshake is allowed through. Split out from `decide` so the
+/// window/cap arithmetic can be unit tested without a live `ConfigFile`.
+fn apply_inbound_window(
+    count: usize,
+    window_start: Instant,
+    now: Instant,
+    max: usize,
+) -> (usize, Instant, bool) {
+    let (mut count, window_start) =
+        if now.duration_since(window_start) >= Duration::from_secs(INBOUND_WINDOW_SECS) {
+            (0, now)
+        } else {
+            (count, window_start)
+        };
+    if count >= max {
+        return (count, window_start, false);
+    }
+    count += 1;
+    (count, window_start, true)
+}
+
+/// Whether a window last reset at `window_start` is still within `INBOUND_IDLE_EVICTION_SECS` of
+/// `now`, i.e. should survive `evict_idle`.
+fn is_fresh(window_start: Instant, now: Instant) -> bool {
+    now.duration_since(window_start) < Duration::from_secs(INBOUND_IDLE_EVICTION_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ip(last_octet: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, last_octet))
+    }
+
+    #[test]
+    fn is_whitelisted_allows_everything_when_the_list_is_empty() {
+        assert!(is_whitelisted(&[], ip(1)));
+    }
+
+    #[test]
+    fn is_whitelisted_allows_only_listed_ips_once_the_list_is_non_empty() {
+        let whitelist = vec![ip(1)];
+        assert!(is_whitelisted(&whitelist, ip(1)));
+        assert!(!is_whitelisted(&whitelist, ip(2)));
+    }
+
+    #[test]
+    fn apply_inbound_window_allows_handshakes_under_the_cap() {
+        let now = Instant::now();
+        let (count, window_start, allowed) = apply_inbound_window(0, now, now, 2);
+        assert_eq!(count, 1);
+        assert_eq!(window_start, now);
+        assert!(allowed);
+    }
+
+    #[test]
+    fn apply_inbound_window_drops_handshakes_once_the_cap_is_reached() {
+        let now = Instant::now();
+        let (count, _window_start, allowed) = apply_inbound_window(2, now, now, 2);
+        assert_eq!(count, 2);
+        assert!(!allowed);
+    }
+
+    #[test]
+    fn apply_inbound_window_resets_the_count_once_the_window_elapses() {
+        let window_start = Instant::now() - Duration::from_secs(INBOUND_WINDOW_SECS);
+        let now = Instant::now();
+        let (count, new_window_start, allowed) = apply_inbound_window(2, window_start, now, 2);
+        assert_eq!(count, 1);
+        assert_eq!(new_window_start, now);
+        assert!(allowed);
+    }
+
+    #[test]
+    fn apply_inbound_window_keeps_the_count_just_under_the_window_boundary() {
+        let window_start = Instant::now() - Duration::from_secs(INBOUND_WINDOW_SECS - 1);
+        let now = Instant::now();
+        let (count, new_window_start, allowed) = apply_inbound_window(2, window_start, now, 2);
+        assert_eq!(count, 2);
+        assert_eq!(new_window_start, window_start);
+        assert!(!allowed);
+    }
+
+    #[test]
+    fn is_fresh_keeps_windows_touched_within_the_idle_eviction_window() {
+        let now = Instant::now();
+        let window_start = now - Duration::from_secs(INBOUND_IDLE_EVICTION_SECS - 1);
+        assert!(is_fresh(window_start, now));
+    }
+
+    #[test]
+    fn is_fresh_evicts_windows_idle_for_the_full_eviction_window() {
+        let now = Instant::now();
+        let window_start = now - Duration::from_secs(INBOUND_IDLE_EVICTION_SECS);
+        assert!(!is_fresh(window_start, now));
+    }
+
+    #[test]
+    fn apply_inbound_window_accumulates_across_repeated_calls_within_the_same_window() {
+        let now = Instant::now();
+        let (mut count, mut window_start) = (0, now);
+        let mut allowed = true;
+        for _ in 0..2 {
+            let result = apply_inbound_window(count, window_start, now, 2);
+            count = result.0;
+            window_start = result.1;
+            allowed = result.2;
+        }
+        assert_eq!(count, 2);
+        assert!(allowed);
+
+        let (count, _window_start, allowed) = apply_inbound_window(count, window_start, now, 2);
+        assert_eq!(count, 2);
+        assert!(!allowed);
+    }
+}
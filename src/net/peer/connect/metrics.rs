@@ -0,0 +1,190 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+//! Prometheus/open-metrics-compatible counters and histograms for `connect()`. Everything here
+//! is gated behind the `metrics` cargo feature; with the feature off, `ConnectMetrics` is a
+//! zero-sized no-op so the default build pays nothing for it.
+
+use net::peer::connect::connect::SingleConnectionError;
+use priv_prelude::*;
+use std::time::Duration;
+
+/// Which transport a connection attempt/success is attributed to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectKind {
+    Direct,
+    Rendezvous,
+    Relay,
+    /// We didn't dial out at all; the peer reached us first on `peer_rx`.
+    Incoming,
+}
+
+#[cfg(feature = "metrics")]
+mod enabled {
+    use super::ConnectKind;
+    use net::peer::connect::connect::SingleConnectionError;
+    use open_metrics_client::encoding::text::Encode;
+    use open_metrics_client::metrics::counter::Counter;
+    use open_metrics_client::metrics::family::Family;
+    use open_metrics_client::metrics::histogram::Histogram;
+    use open_metrics_client::registry::Registry;
+    use priv_prelude::*;
+    use std::time::Duration;
+
+    #[derive(Clone, Debug, Hash, PartialEq, Eq, Encode)]
+    struct NetworkLabel {
+        name_hash: String,
+    }
+
+    #[derive(Clone, Debug, Hash, PartialEq, Eq, Encode)]
+    struct TransportLabel {
+        name_hash: String,
+        transport: &'static str,
+    }
+
+    #[derive(Clone, Debug, Hash, PartialEq, Eq, Encode)]
+    struct FailureLabel {
+        name_hash: String,
+        reason: &'static str,
+    }
+
+    /// Registry handle threaded through `connect()` and `start_rendezvous_connect()` so operators
+    /// can scrape which transport is actually carrying traffic and how the `TIMEOUT_SEC` budget
+    /// is being spent.
+    #[derive(Clone)]
+    pub struct ConnectMetrics {
+        attempts: Family<TransportLabel, Counter>,
+        successes: Family<TransportLabel, Counter>,
+        failures: Family<FailureLabel, Counter>,
+        time_to_connect: Family<NetworkLabel, Histogram>,
+    }
+
+    impl ConnectMetrics {
+        /// Registers every metric under `registry` and returns a handle to record against them.
+        pub fn register(registry: &mut Registry) -> ConnectMetrics {
+            let attempts = Family::default();
+            registry.register(
+                "crust_connect_attempts",
+                "Connection attempts started, by transport",
+                Box::new(attempts.clone()),
+            );
+            let successes = Family::default();
+            registry.register(
+                "crust_connect_successes",
+                "Connection attempts that reached a handshaken peer, by transport",
+                Box::new(successes.clone()),
+            );
+            let failures = Family::default();
+            registry.register(
+                "crust_connect_failures",
+                "Connection attempts that failed, by failure reason",
+                Box::new(failures.clone()),
+            );
+            let time_to_connect = Family::new_with_constructor(|| {
+                Histogram::new(vec![1.0, 2.5, 5.0, 10.0, 20.0, 40.0, 60.0].into_iter())
+            });
+            registry.register(
+                "crust_connect_time_to_connect_seconds",
+                "Time from the start of connect() to a handshaken peer",
+                Box::new(time_to_connect.clone()),
+            );
+            ConnectMetrics {
+                attempts: attempts,
+                successes: successes,
+                failures: failures,
+                time_to_connect: time_to_connect,
+            }
+        }
+
+        pub fn record_attempt(&self, name_hash: NameHash, kind: ConnectKind) {
+            self.attempts
+                .get_or_create(&TransportLabel {
+                    name_hash: format!("{:?}", name_hash),
+                    transport: transport_label(kind),
+                })
+                .inc();
+        }
+
+        pub fn record_success(&self, name_hash: NameHash, kind: ConnectKind) {
+            self.successes
+                .get_or_create(&TransportLabel {
+                    name_hash: format!("{:?}", name_hash),
+                    transport: transport_label(kind),
+                })
+                .inc();
+        }
+
+        pub fn record_failure(&self, name_hash: NameHash, err: &SingleConnectionError) {
+            self.failures
+                .get_or_create(&FailureLabel {
+                    name_hash: format!("{:?}", name_hash),
+                    reason: failure_label(err),
+                })
+                .inc();
+        }
+
+        pub fn record_time_to_connect(&self, name_hash: NameHash, elapsed: Duration) {
+            let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            self.time_to_connect
+                .get_or_create(&NetworkLabel { name_hash: format!("{:?}", name_hash) })
+                .observe(secs);
+        }
+    }
+
+    fn transport_label(kind: ConnectKind) -> &'static str {
+        match kind {
+            ConnectKind::Direct => "direct",
+            ConnectKind::Rendezvous => "rendezvous",
+            ConnectKind::Relay => "relay",
+            ConnectKind::Incoming => "incoming",
+        }
+    }
+
+    fn failure_label(err: &SingleConnectionError) -> &'static str {
+        match *err {
+            SingleConnectionError::Io(..) => "io",
+            SingleConnectionError::Socket(..) => "socket",
+            SingleConnectionError::ConnectionDropped => "connection_dropped",
+            SingleConnectionError::InvalidUid(..) => "invalid_uid",
+            SingleConnectionError::InvalidNameHash(..) => "invalid_name_hash",
+            SingleConnectionError::UnexpectedMessage => "unexpected_message",
+            SingleConnectionError::TimedOut => "timed_out",
+            SingleConnectionError::DeadChannel => "dead_channel",
+            SingleConnectionError::RendezvousConnect(..) => "rendezvous_connect",
+            SingleConnectionError::RelayUnavailable => "relay_unavailable",
+            SingleConnectionError::Rejected(..) => "rejected",
+            SingleConnectionError::RateLimited => "rate_limited",
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub use self::enabled::ConnectMetrics;
+
+/// No-op stand-in used when the `metrics` feature is disabled, so `connect()` doesn't need two
+/// code paths: it just always has a `ConnectMetrics` handle to call into.
+#[cfg(not(feature = "metrics"))]
+#[derive(Clone, Default)]
+pub struct ConnectMetrics;
+
+#[cfg(not(feature = "metrics"))]
+impl ConnectMetrics {
+    pub fn record_attempt(&self, _name_hash: NameHash, _kind: ConnectKind) {}
+    pub fn record_success(&self, _name_hash: NameHash, _kind: ConnectKind) {}
+    pub fn record_failure(&self, _name_hash: NameHash, _err: &SingleConnectionError) {}
+    pub fn record_time_to_connect(&self, _name_hash: NameHash, _elapsed: Duration) {}
+}
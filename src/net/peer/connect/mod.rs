@@ -0,0 +1,35 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+mod demux;
+mod handshake_message;
+
+pub mod bootstrap;
+pub mod connect;
+pub mod limiter;
+pub mod metrics;
+pub mod rate_limiter;
+pub mod relay;
+pub mod rendezvous;
+
+pub use self::bootstrap::{bootstrap, BootstrapCache, BootstrapError};
+pub use self::connect::{connect, start_rendezvous_connect, ConnectError, SingleConnectionError};
+pub use self::limiter::{ConnectionDecision, ConnectionLimiter, PublicHandshakeInfo};
+pub use self::metrics::{ConnectKind, ConnectMetrics};
+pub use self::rate_limiter::RateLimiter;
+pub use self::relay::{RelayError, RelayNode};
+pub use self::rendezvous::{RendezvousError, RendezvousServer};
@@ -0,0 +1,138 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use priv_prelude::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// IP buckets that haven't needed a token in this long are dropped, so a node that floods us
+/// once and never comes back doesn't sit in memory forever.
+const IDLE_EVICTION_SECS: u64 = 300;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A per-source-IP token bucket guarding `direct_incoming`, à la wireguard-rs's handshake rate
+/// limiting: every incoming `(socket, connect_request)` costs one token before we even look at
+/// its name-hash/uid, refilled at `ConfigFile`'s `handshakes_per_sec` up to `handshake_burst_size`.
+pub struct RateLimiter {
+    config: ConfigFile,
+    buckets: RefCell<HashMap<IpAddr, Bucket>>,
+    last_eviction: Cell<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: ConfigFile) -> RateLimiter {
+        RateLimiter {
+            config: config,
+            buckets: RefCell::new(HashMap::new()),
+            last_eviction: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Deducts one token from `addr`'s bucket, refilling it first for however long it's been
+    /// since we last touched it. Returns `false` when the bucket is empty, i.e. the caller
+    /// should drop the socket without replying.
+    pub fn try_acquire(&self, addr: IpAddr) -> bool {
+        let now = Instant::now();
+        self.evict_idle(now);
+
+        let config = self.config.read();
+        let rate = config.handshakes_per_sec;
+        let burst = config.handshake_burst_size;
+
+        let mut buckets = self.buckets.borrow_mut();
+        let bucket = buckets.entry(addr).or_insert_with(|| {
+            Bucket {
+                tokens: burst,
+                last_refill: now,
+            }
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill);
+        let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        bucket.tokens = refill_tokens(bucket.tokens, elapsed_secs, rate, burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn evict_idle(&self, now: Instant) {
+        if now.duration_since(self.last_eviction.get()) < Duration::from_secs(IDLE_EVICTION_SECS) {
+            return;
+        }
+        self.last_eviction.set(now);
+        self.buckets.borrow_mut().retain(|_addr, bucket| {
+            is_fresh(bucket.last_refill, now)
+        });
+    }
+}
+
+/// Refills a bucket by `elapsed_secs * rate` tokens, capped at `burst`. Split out from
+/// `try_acquire` so the arithmetic can be unit tested without a live `ConfigFile`.
+fn refill_tokens(current: f64, elapsed_secs: f64, rate: f64, burst: f64) -> f64 {
+    (current + elapsed_secs * rate).min(burst)
+}
+
+/// Whether a bucket last touched at `last_refill` is still within `IDLE_EVICTION_SECS` of `now`,
+/// i.e. should survive `evict_idle`.
+fn is_fresh(last_refill: Instant, now: Instant) -> bool {
+    now.duration_since(last_refill) < Duration::from_secs(IDLE_EVICTION_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refill_tokens_accumulates_at_the_configured_rate() {
+        assert_eq!(refill_tokens(0.0, 1.0, 5.0, 10.0), 5.0);
+        assert_eq!(refill_tokens(8.0, 1.0, 5.0, 10.0), 10.0);
+    }
+
+    #[test]
+    fn refill_tokens_is_capped_at_burst_even_after_a_long_idle() {
+        assert_eq!(refill_tokens(0.0, 1_000.0, 5.0, 10.0), 10.0);
+    }
+
+    #[test]
+    fn refill_tokens_is_a_no_op_with_zero_elapsed_time() {
+        assert_eq!(refill_tokens(3.0, 0.0, 5.0, 10.0), 3.0);
+    }
+
+    #[test]
+    fn is_fresh_keeps_buckets_touched_within_the_idle_window() {
+        let now = Instant::now();
+        let last_refill = now - Duration::from_secs(IDLE_EVICTION_SECS - 1);
+        assert!(is_fresh(last_refill, now));
+    }
+
+    #[test]
+    fn is_fresh_evicts_buckets_idle_for_the_full_window() {
+        let now = Instant::now();
+        let last_refill = now - Duration::from_secs(IDLE_EVICTION_SECS);
+        assert!(!is_fresh(last_refill, now));
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use futures::sync::mpsc::UnboundedReceiver;
+use net::peer::connect::connect::{validate_connect_request, SingleConnectionError};
+use net::peer::connect::demux::DemuxMessage;
+use net::peer::connect::handshake_message::{ConnectRequest, HandshakeMessage};
+use priv_prelude::*;
+use serde_json;
+use std::collections::HashMap;
+use std::time::Instant;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum RelayError {
+        Socket(e: SocketError) {
+            description("socket error relaying between two peers")
+            display("socket error relaying between two peers: {}", e)
+            cause(e)
+        }
+        UnexpectedMessage {
+            description("relay link carried a handshake message variant other than RelayData")
+        }
+        BandwidthExceeded {
+            description("relay's configured bytes/sec budget was exhausted")
+        }
+    }
+}
+
+/// Attempts to reach `their_id` via every relay in `relay_addrs`, racing them the same way
+/// `direct_connections` races `their_info.for_direct`. For symmetric-NAT peers where neither
+/// direct dial-out nor p2p hole punching can succeed, a relay both sides already have a direct
+/// connection to gives them a path anyway.
+pub fn relay_connection<UID: Uid>(
+    handle: &Handle,
+    name_hash: NameHash,
+    our_connect_request: ConnectRequest<UID>,
+    their_id: UID,
+    relay_addrs: Vec<SocketAddr>,
+) -> BoxStream<(Socket<HandshakeMessage<UID>>, UID), SingleConnectionError> {
+    if relay_addrs.is_empty() {
+        return stream::once(Err(SingleConnectionError::RelayUnavailable)).into_boxed();
+    }
+
+    let handle = handle.clone();
+    stream::futures_unordered(relay_addrs.into_iter().map(move |relay_addr| {
+        let handle = handle.clone();
+        let our_connect_request = our_connect_request.clone();
+        TcpStream::connect(&relay_addr, &handle)
+            .map_err(SingleConnectionError::Io)
+            .and_then(move |stream| {
+                let peer_addr = unwrap!(stream.peer_addr());
+                let socket = Socket::wrap_tcp(&handle, stream, peer_addr);
+                socket
+                    .send((
+                        0,
+                        HandshakeMessage::RelayRequest {
+                            uid: our_connect_request.uid,
+                            target_uid: their_id,
+                            name_hash: name_hash,
+                        },
+                    ))
+                    .map_err(SingleConnectionError::Socket)
+                    .map(move |socket| (socket, our_connect_request))
+            })
+            .and_then(move |(socket, our_connect_request)| {
+                // Everything after the `RelayRequest` travels as `RelayData`, since that's all
+                // a `RelayNode` will pipe through to our partner once it's matched us up.
+                let bytes = unwrap!(serde_json::to_vec(&our_connect_request));
+                socket
+                    .send((0, HandshakeMessage::RelayData(bytes)))
+                    .map_err(SingleConnectionError::Socket)
+            })
+            .and_then(|socket| {
+                socket.into_future().map_err(
+                    |(err, _socket)| SingleConnectionError::Socket(err),
+                )
+            })
+            .and_then(move |(msg_opt, socket)| match msg_opt {
+                None => Err(SingleConnectionError::ConnectionDropped),
+                Some(HandshakeMessage::RelayData(bytes)) => {
+                    let connect_request: ConnectRequest<UID> = serde_json::from_slice(&bytes)
+                        .map_err(|_e| SingleConnectionError::UnexpectedMessage)?;
+                    validate_connect_request(their_id, name_hash, &connect_request)?;
+                    Ok((socket, connect_request.uid))
+                }
+                Some(_msg) => Err(SingleConnectionError::UnexpectedMessage),
+            })
+    }).collect::<Vec<_>>())
+        .into_boxed()
+}
+
+/// Matches up the two halves of a relayed connection by uid pair and pipes `RelayData` between
+/// them, bounded both by `max_concurrent` simultaneously-relayed pairs and by a shared
+/// `max_bytes_per_sec` budget, so a handful of peers can't monopolise this node's bandwidth
+/// acting as a relay for everyone else.
+pub struct RelayNode<UID: Uid> {
+    name_hash: NameHash,
+    max_concurrent: usize,
+    active: usize,
+    pending: HashMap<(UID, UID), Socket<HandshakeMessage<UID>>>,
+    bandwidth: Rc<RefCell<Bandwidth>>,
+}
+
+impl<UID: Uid> RelayNode<UID> {
+    pub fn new(name_hash: NameHash, max_concurrent: usize, max_bytes_per_sec: f64) -> RelayNode<UID> {
+        RelayNode {
+            name_hash: name_hash,
+            max_concurrent: max_concurrent,
+            active: 0,
+            pending: HashMap::new(),
+            bandwidth: Rc::new(RefCell::new(Bandwidth::new(max_bytes_per_sec))),
+        }
+    }
+
+    /// Drives the relay: handles every `RelayRequest` that arrives on `incoming`, for as long as
+    /// this node chooses to offer the relay role.
+    pub fn run(
+        self,
+        handle: &Handle,
+        incoming: UnboundedReceiver<DemuxMessage<UID>>,
+    ) -> BoxFuture<(), RelayError> {
+        let handle = handle.clone();
+        let relay = Rc::new(RefCell::new(self));
+        incoming
+            .map_err(|()| unreachable!())
+            .infallible::<RelayError>()
+            .for_each(move |(socket, message)| {
+                if let HandshakeMessage::RelayRequest { uid, target_uid, name_hash } = message {
+                    if name_hash != relay.borrow().name_hash {
+                        // Requester claims a different network than we're relaying for; refuse
+                        // to pair it rather than let `validate_connect_request` catch it only
+                        // after we've already spent a `max_concurrent` slot piping its bytes.
+                        return Ok(());
+                    }
+                    let partner = relay.borrow_mut().pending.remove(&(target_uid, uid));
+                    match partner {
+                        Some(partner_socket) => {
+                            let can_relay = {
+                                let mut relay = relay.borrow_mut();
+                                let can_relay = has_capacity(relay.active, relay.max_concurrent);
+                                if can_relay {
+                                    relay.active += 1;
+                                }
+                                can_relay
+                            };
+                            if can_relay {
+                                let bandwidth = relay.borrow().bandwidth.clone();
+                                let relay = relay.clone();
+                                let task = pipe_pair(partner_socket, socket, bandwidth).then(
+                                    move |_result| {
+                                        relay.borrow_mut().active -= 1;
+                                        Ok(())
+                                    },
+                                );
+                                handle.spawn(task);
+                            }
+                        }
+                        None => {
+                            relay.borrow_mut().pending.insert((uid, target_uid), socket);
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .into_boxed()
+    }
+}
+
+/// Forwards every `RelayData` frame read from `a` onto `b` and vice versa until either side
+/// closes, metering every frame against `bandwidth`.
+fn pipe_pair<UID: Uid>(
+    a: Socket<HandshakeMessage<UID>>,
+    b: Socket<HandshakeMessage<UID>>,
+    bandwidth: Rc<RefCell<Bandwidth>>,
+) -> BoxFuture<(), RelayError> {
+    let (a_sink, a_stream) = a.split();
+    let (b_sink, b_stream) = b.split();
+    let bandwidth_a = bandwidth.clone();
+    let a_to_b = a_stream
+        .map_err(RelayError::Socket)
+        .and_then(move |message| meter_relay_data(message, &bandwidth_a))
+        .forward(b_sink.sink_map_err(RelayError::Socket));
+    let b_to_a = b_stream
+        .map_err(RelayError::Socket)
+        .and_then(move |message| meter_relay_data(message, &bandwidth))
+        .forward(a_sink.sink_map_err(RelayError::Socket));
+    a_to_b.join(b_to_a).map(|_| ()).into_boxed()
+}
+
+/// Whether another pair can be relayed given `max_concurrent`. Split out from `RelayNode::run` so
+/// the concurrency cap is unit testable without a live `RelayNode<UID>`.
+fn has_capacity(active: usize, max_concurrent: usize) -> bool {
+    active < max_concurrent
+}
+
+/// Rejects anything that isn't `RelayData` (an established relay link should never carry another
+/// variant) and deducts its length from `bandwidth`, rejecting the frame once that budget is
+/// exhausted.
+fn meter_relay_data<UID: Uid>(
+    message: HandshakeMessage<UID>,
+    bandwidth: &Rc<RefCell<Bandwidth>>,
+) -> Result<HandshakeMessage<UID>, RelayError> {
+    let len = match message {
+        HandshakeMessage::RelayData(ref bytes) => bytes.len(),
+        _ => return Err(RelayError::UnexpectedMessage),
+    };
+    if bandwidth.borrow_mut().try_acquire(len) {
+        Ok(message)
+    } else {
+        Err(RelayError::BandwidthExceeded)
+    }
+}
+
+/// A token bucket metering total relayed bytes/sec across every pair a `RelayNode` is currently
+/// piping, à la `RateLimiter`'s per-IP handshake budget. This is the node-wide counterpart to
+/// `max_concurrent`, which only bounds the number of concurrently-relayed pairs, not their
+/// throughput.
+struct Bandwidth {
+    bytes_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bandwidth {
+    fn new(bytes_per_sec: f64) -> Bandwidth {
+        Bandwidth {
+            bytes_per_sec: bytes_per_sec,
+            tokens: bytes_per_sec,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Deducts `len` bytes from the budget, refilling it first for however long it's been since
+    /// we last touched it. Returns `false` once the budget can't cover `len`, i.e. the caller
+    /// should refuse to forward the frame.
+    fn try_acquire(&mut self, len: usize) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed_secs * self.bytes_per_sec).min(self.bytes_per_sec);
+        self.last_refill = now;
+
+        if self.tokens >= len as f64 {
+            self.tokens -= len as f64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn has_capacity_allows_pairs_under_the_concurrency_cap() {
+        assert!(has_capacity(0, 2));
+        assert!(has_capacity(1, 2));
+    }
+
+    #[test]
+    fn has_capacity_refuses_once_active_reaches_max_concurrent() {
+        assert!(!has_capacity(2, 2));
+        assert!(!has_capacity(3, 2));
+    }
+
+    #[test]
+    fn try_acquire_deducts_available_tokens() {
+        let mut bandwidth = Bandwidth::new(1000.0);
+        assert!(bandwidth.try_acquire(400));
+        assert_eq!(bandwidth.tokens, 600.0);
+    }
+
+    #[test]
+    fn try_acquire_refuses_a_frame_larger_than_the_remaining_budget() {
+        let mut bandwidth = Bandwidth::new(1000.0);
+        assert!(bandwidth.try_acquire(900));
+        assert!(!bandwidth.try_acquire(200));
+        // The refused call still refills for the few nanoseconds since the first one, so the
+        // budget is 100 plus a negligible amount, with nothing deducted.
+        assert!(bandwidth.tokens >= 100.0 && bandwidth.tokens < 101.0);
+    }
+
+    #[test]
+    fn try_acquire_refills_over_time_up_to_bytes_per_sec() {
+        let mut bandwidth = Bandwidth::new(1000.0);
+        assert!(bandwidth.try_acquire(1000));
+        bandwidth.last_refill = Instant::now() - Duration::from_millis(500);
+        assert!(bandwidth.try_acquire(400));
+        assert!(!bandwidth.try_acquire(400));
+    }
+
+    #[test]
+    fn try_acquire_never_exceeds_bytes_per_sec_even_after_a_long_idle() {
+        let mut bandwidth = Bandwidth::new(1000.0);
+        bandwidth.last_refill = Instant::now() - Duration::from_secs(1000);
+        assert!(bandwidth.try_acquire(1000));
+        assert!(!bandwidth.try_acquire(1));
+    }
+}
@@ -0,0 +1,551 @@
+// Copyright 2017 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under (1) the MaidSafe.net Commercial License,
+// version 1.0 or later, or (2) The General Public License (GPL), version 3, depending on which
+// licence you accepted on initial access to the Software (the "Licences").
+//
+// By contributing code to the SAFE Network Software, or to this project generally, you agree to be
+// bound by the terms of the MaidSafe Contributor Agreement.  This, along with the Licenses can be
+// found in the root directory of this project at LICENSE, COPYING and CONTRIBUTOR.
+//
+// Unless required by applicable law or agreed to in writing, the SAFE Network Software distributed
+// under the GPL Licence is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.
+//
+// Please review the Licences for the specific language governing permissions and limitations
+// relating to use of the SAFE Network Software.
+
+use futures::sync::mpsc::UnboundedReceiver;
+use net::peer::connect::demux::DemuxMessage;
+use net::peer::connect::handshake_message::{HandshakeMessage, RendezvousCookie};
+use priv_prelude::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Max number of registrations handed back in a single `DiscoverResponse` page.
+const DISCOVER_PAGE_SIZE: usize = 64;
+/// Bound on how many live registrations a single namespace will hold, so a flood of `Register`
+/// requests under one namespace can't grow `registrations` without limit.
+const MAX_REGISTRATIONS_PER_NAMESPACE: usize = 1024;
+/// Bound on how many live registrations a single source IP may hold across all namespaces, so
+/// one peer can't exhaust the namespace cap on its own with distinct uid/namespace pairs.
+const MAX_REGISTRATIONS_PER_IP: usize = 16;
+/// `ttl_secs` is caller-supplied and otherwise unbounded; clamp it so a registration can't be
+/// parked here indefinitely.
+const MAX_TTL_SECS: u64 = 60 * 60;
+/// `gc_namespace` only ever sweeps the namespace a request touches, so an IP that registered
+/// into namespaces nobody's queried or re-registered into since would otherwise stay pinned at
+/// its `registrations_per_ip` count forever even once every one of those registrations has
+/// expired. A full sweep across all namespaces, at most this often, keeps the per-IP count live.
+const GC_ALL_INTERVAL_SECS: u64 = 60;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum RendezvousError {
+        Io(e: io::Error) {
+            description("io error running rendezvous server")
+            display("io error running rendezvous server: {}", e)
+            cause(e)
+        }
+        Socket(e: SocketError) {
+            description("socket error talking to rendezvous server")
+            display("socket error talking to rendezvous server: {}", e)
+            cause(e)
+        }
+        InvalidNameHash(name_hash: NameHash) {
+            description("rendezvous peer is from a different network")
+            display("rendezvous peer is from a different network. Invalid name hash == {:?}", name_hash)
+        }
+        ConnectionDropped {
+            description("the rendezvous server/client dropped the connection")
+        }
+        UnexpectedMessage {
+            description("rendezvous peer sent us an unexpected message variant")
+        }
+    }
+}
+
+/// A single live `(namespace, uid) -> info` mapping held by a `RendezvousServer`.
+struct Registration<UID: Uid> {
+    uid: UID,
+    addr: IpAddr,
+    info: PubConnectionInfo<UID>,
+    expiry: Instant,
+}
+
+/// A lightweight rendezvous-point server, modeled on libp2p's rendezvous protocol: peers
+/// `register()` their `PubConnectionInfo` under a namespace and other peers `discover()` it,
+/// so they don't have to exchange connection info out-of-band before calling `connect()`.
+pub struct RendezvousServer<UID: Uid> {
+    name_hash: NameHash,
+    registrations: HashMap<String, Vec<Registration<UID>>>,
+    registrations_per_ip: HashMap<IpAddr, usize>,
+    last_gc_all: Cell<Instant>,
+}
+
+impl<UID: Uid> RendezvousServer<UID> {
+    pub fn new(name_hash: NameHash) -> RendezvousServer<UID> {
+        RendezvousServer {
+            name_hash: name_hash,
+            registrations: HashMap::new(),
+            registrations_per_ip: HashMap::new(),
+            last_gc_all: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Drives the server: handles every incoming registration/discovery request until
+    /// `incoming` is exhausted (which, for a long-running server, is never).
+    pub fn run(
+        self,
+        handle: &Handle,
+        incoming: UnboundedReceiver<DemuxMessage<UID>>,
+    ) -> BoxFuture<(), RendezvousError> {
+        let handle = handle.clone();
+        let server = Rc::new(RefCell::new(self));
+        incoming
+            .map_err(|()| unreachable!())
+            .infallible::<RendezvousError>()
+            .for_each(move |(socket, message)| {
+                let server = server.clone();
+                let task = handle_request(server, socket, message)
+                    .then(|_result| Ok(()));
+                handle.spawn(task);
+                Ok(())
+            })
+            .into_boxed()
+    }
+
+    /// Registers `info` under `namespace`, silently refusing (rather than erroring, since
+    /// `Register` has no reply) once `addr` or `namespace` is already at its cap. `ttl` is
+    /// clamped to `MAX_TTL_SECS` regardless of what the caller asked for.
+    fn register(
+        &mut self,
+        namespace: String,
+        uid: UID,
+        addr: IpAddr,
+        info: PubConnectionInfo<UID>,
+        ttl: Duration,
+    ) {
+        self.gc_all();
+        self.gc_namespace(&namespace);
+        self.remove(&namespace, uid);
+
+        let per_ip = self.registrations_per_ip.get(&addr).cloned().unwrap_or(0);
+        if per_ip >= MAX_REGISTRATIONS_PER_IP {
+            return;
+        }
+        let registrations = self.registrations.entry(namespace).or_insert_with(Vec::new);
+        if registrations.len() >= MAX_REGISTRATIONS_PER_NAMESPACE {
+            return;
+        }
+
+        let ttl = clamp_ttl(ttl);
+        registrations.push(Registration {
+            uid: uid,
+            addr: addr,
+            info: info,
+            expiry: Instant::now() + ttl,
+        });
+        *self.registrations_per_ip.entry(addr).or_insert(0) += 1;
+    }
+
+    fn unregister(&mut self, namespace: &str, uid: UID) {
+        self.remove(namespace, uid);
+    }
+
+    /// Drops `uid`'s registration from `namespace`, if any, and reflects that in
+    /// `registrations_per_ip` so the IP's quota is freed up again.
+    fn remove(&mut self, namespace: &str, uid: UID) {
+        let removed_addr = match self.registrations.get_mut(namespace) {
+            Some(registrations) => {
+                let pos = registrations.iter().position(|r| r.uid == uid);
+                pos.map(|pos| registrations.remove(pos).addr)
+            }
+            None => None,
+        };
+        if let Some(addr) = removed_addr {
+            self.decrement_ip(addr);
+        }
+    }
+
+    fn decrement_ip(&mut self, addr: IpAddr) {
+        decrement_ip_count(&mut self.registrations_per_ip, addr);
+    }
+
+    fn discover(
+        &mut self,
+        namespace: &str,
+        cookie: Option<RendezvousCookie>,
+    ) -> (Vec<PubConnectionInfo<UID>>, Option<RendezvousCookie>) {
+        self.gc_namespace(namespace);
+        let offset = cookie.map(|c| c.0).unwrap_or(0);
+        let registrations = match self.registrations.get(namespace) {
+            Some(registrations) => registrations,
+            None => return (Vec::new(), None),
+        };
+        let page: Vec<_> = registrations
+            .iter()
+            .skip(offset)
+            .take(DISCOVER_PAGE_SIZE)
+            .map(|r| r.info.clone())
+            .collect();
+        let next_cookie = next_page_cookie(registrations.len(), offset, page.len());
+        (page, next_cookie)
+    }
+
+    /// Drops every registration in `namespace` whose TTL has elapsed.
+    fn gc_namespace(&mut self, namespace: &str) {
+        let expired_addrs: Vec<IpAddr> = match self.registrations.get_mut(namespace) {
+            Some(registrations) => {
+                let now = Instant::now();
+                let mut expired = Vec::new();
+                registrations.retain(|r| {
+                    if r.expiry > now {
+                        true
+                    } else {
+                        expired.push(r.addr);
+                        false
+                    }
+                });
+                expired
+            }
+            None => Vec::new(),
+        };
+        for addr in expired_addrs {
+            self.decrement_ip(addr);
+        }
+    }
+
+    /// Sweeps every namespace for expired registrations, at most once per
+    /// `GC_ALL_INTERVAL_SECS`, so `registrations_per_ip` reflects namespaces nobody's
+    /// registered/discovered into recently rather than only the one touched by the current
+    /// request.
+    fn gc_all(&mut self) {
+        let now = Instant::now();
+        if !due_for_gc(self.last_gc_all.get(), now) {
+            return;
+        }
+        self.last_gc_all.set(now);
+
+        let namespaces: Vec<String> = self.registrations.keys().cloned().collect();
+        for namespace in namespaces {
+            self.gc_namespace(&namespace);
+        }
+        self.registrations.retain(|_namespace, registrations| {
+            !registrations.is_empty()
+        });
+    }
+}
+
+fn handle_request<UID: Uid>(
+    server: Rc<RefCell<RendezvousServer<UID>>>,
+    socket: Socket<HandshakeMessage<UID>>,
+    message: HandshakeMessage<UID>,
+) -> BoxFuture<(), RendezvousError> {
+    let name_hash = server.borrow().name_hash;
+    let response = match message {
+        HandshakeMessage::Register {
+            namespace,
+            uid,
+            name_hash: their_name_hash,
+            info,
+            ttl_secs,
+        } => {
+            if their_name_hash != name_hash {
+                return future::err(RendezvousError::InvalidNameHash(their_name_hash)).into_boxed();
+            }
+            let addr = match socket.peer_addr() {
+                Ok(addr) => addr.ip(),
+                Err(e) => return future::err(RendezvousError::Socket(e)).into_boxed(),
+            };
+            server
+                .borrow_mut()
+                .register(namespace, uid, addr, info, Duration::from_secs(ttl_secs));
+            None
+        }
+        HandshakeMessage::Unregister {
+            namespace,
+            uid,
+            name_hash: their_name_hash,
+        } => {
+            if their_name_hash != name_hash {
+                return future::err(RendezvousError::InvalidNameHash(their_name_hash)).into_boxed();
+            }
+            server.borrow_mut().unregister(&namespace, uid);
+            None
+        }
+        HandshakeMessage::Discover {
+            namespace,
+            name_hash: their_name_hash,
+            cookie,
+        } => {
+            if their_name_hash != name_hash {
+                return future::err(RendezvousError::InvalidNameHash(their_name_hash)).into_boxed();
+            }
+            let (registrations, next_cookie) = server.borrow_mut().discover(&namespace, cookie);
+            Some(HandshakeMessage::DiscoverResponse {
+                registrations: registrations,
+                cookie: next_cookie,
+            })
+        }
+        _ => return future::err(RendezvousError::UnexpectedMessage).into_boxed(),
+    };
+
+    match response {
+        Some(response) => socket
+            .send((0, response))
+            .map(|_socket| ())
+            .map_err(RendezvousError::Socket)
+            .into_boxed(),
+        None => future::ok(()).into_boxed(),
+    }
+}
+
+/// Registers `our_info` under `namespace` with the rendezvous server at `server_addr`, so other
+/// peers calling `discover()` on the same namespace can find it and feed it straight into
+/// `connect()`. The registration expires after `ttl` (capped by the server at `MAX_TTL_SECS`)
+/// unless renewed with another call.
+pub fn register<UID: Uid>(
+    handle: &Handle,
+    name_hash: NameHash,
+    server_addr: &SocketAddr,
+    namespace: String,
+    our_uid: UID,
+    our_info: PubConnectionInfo<UID>,
+    ttl: Duration,
+) -> BoxFuture<(), RendezvousError> {
+    let handle = handle.clone();
+    TcpStream::connect(server_addr, &handle)
+        .map_err(RendezvousError::Io)
+        .and_then(move |stream| {
+            let peer_addr = unwrap!(stream.peer_addr());
+            let socket = Socket::wrap_tcp(&handle, stream, peer_addr);
+            socket
+                .send((
+                    0,
+                    HandshakeMessage::Register {
+                        namespace: namespace,
+                        uid: our_uid,
+                        name_hash: name_hash,
+                        info: our_info,
+                        ttl_secs: ttl.as_secs(),
+                    },
+                ))
+                .map_err(RendezvousError::Socket)
+        })
+        .map(|_socket| ())
+        .into_boxed()
+}
+
+/// Queries the rendezvous server at `server_addr` for every live registration under `namespace`,
+/// paging through `DiscoverResponse` cookies until the server reports no more results.
+pub fn discover<UID: Uid>(
+    handle: &Handle,
+    name_hash: NameHash,
+    server_addr: &SocketAddr,
+    namespace: String,
+) -> BoxFuture<Vec<PubConnectionInfo<UID>>, RendezvousError> {
+    let handle = handle.clone();
+    let server_addr = *server_addr;
+    discover_page(handle, name_hash, server_addr, namespace, None, Vec::new())
+}
+
+fn discover_page<UID: Uid>(
+    handle: Handle,
+    name_hash: NameHash,
+    server_addr: SocketAddr,
+    namespace: String,
+    cookie: Option<RendezvousCookie>,
+    mut acc: Vec<PubConnectionInfo<UID>>,
+) -> BoxFuture<Vec<PubConnectionInfo<UID>>, RendezvousError> {
+    TcpStream::connect(&server_addr, &handle)
+        .map_err(RendezvousError::Io)
+        .and_then(move |stream| {
+            let peer_addr = unwrap!(stream.peer_addr());
+            let socket = Socket::wrap_tcp(&handle, stream, peer_addr);
+            socket
+                .send((
+                    0,
+                    HandshakeMessage::Discover {
+                        namespace: namespace.clone(),
+                        name_hash: name_hash,
+                        cookie: cookie,
+                    },
+                ))
+                .map_err(RendezvousError::Socket)
+                .and_then(|socket| {
+                    socket
+                        .into_future()
+                        .map_err(|(err, _socket)| RendezvousError::Socket(err))
+                })
+                .and_then(move |(msg_opt, _socket)| match msg_opt {
+                    None => Err(RendezvousError::ConnectionDropped),
+                    Some(HandshakeMessage::DiscoverResponse {
+                        registrations,
+                        cookie: next_cookie,
+                    }) => {
+                        acc.extend(registrations);
+                        match next_cookie {
+                            Some(next_cookie) => Ok(discover_page(
+                                handle,
+                                name_hash,
+                                server_addr,
+                                namespace,
+                                Some(next_cookie),
+                                acc,
+                            )),
+                            None => Ok(future::ok(acc).into_boxed()),
+                        }
+                    }
+                    Some(_msg) => Err(RendezvousError::UnexpectedMessage),
+                })
+        })
+        .and_then(|f| f)
+        .into_boxed()
+}
+
+/// Caps `ttl` at `MAX_TTL_SECS`. Split out from `register` so it's testable without a live
+/// `RendezvousServer<UID>`.
+fn clamp_ttl(ttl: Duration) -> Duration {
+    ttl.min(Duration::from_secs(MAX_TTL_SECS))
+}
+
+/// Decrements `addr`'s entry in `registrations_per_ip`, removing it entirely once it hits zero
+/// so a long-idle IP doesn't linger in the map forever. Split out from `decrement_ip` so the
+/// per-IP accounting - the thing `gc_all` exists to keep live - is testable without a live `UID`.
+fn decrement_ip_count(registrations_per_ip: &mut HashMap<IpAddr, usize>, addr: IpAddr) {
+    let emptied = match registrations_per_ip.get_mut(&addr) {
+        Some(count) => {
+            *count -= 1;
+            *count == 0
+        }
+        None => false,
+    };
+    if emptied {
+        registrations_per_ip.remove(&addr);
+    }
+}
+
+/// Whether `gc_all` is due to run again, i.e. it's been at least `GC_ALL_INTERVAL_SECS` since
+/// `last_gc_all`.
+fn due_for_gc(last_gc_all: Instant, now: Instant) -> bool {
+    now.duration_since(last_gc_all) >= Duration::from_secs(GC_ALL_INTERVAL_SECS)
+}
+
+/// Pure pagination math for `discover`: given the total number of registrations, the offset the
+/// caller started from and how many were handed back this page, returns the cookie for the next
+/// page, or `None` once every registration has been paged through.
+fn next_page_cookie(total: usize, offset: usize, page_len: usize) -> Option<RendezvousCookie> {
+    if offset + page_len < total {
+        Some(RendezvousCookie(offset + page_len))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn clamp_ttl_leaves_short_ttls_untouched() {
+        let ttl = Duration::from_secs(30);
+        assert_eq!(clamp_ttl(ttl), ttl);
+    }
+
+    #[test]
+    fn clamp_ttl_caps_ttls_longer_than_max() {
+        let ttl = Duration::from_secs(MAX_TTL_SECS * 10);
+        assert_eq!(clamp_ttl(ttl), Duration::from_secs(MAX_TTL_SECS));
+    }
+
+    #[test]
+    fn due_for_gc_is_false_before_the_interval_elapses() {
+        let now = Instant::now();
+        let last_gc_all = now - Duration::from_secs(GC_ALL_INTERVAL_SECS - 1);
+        assert!(!due_for_gc(last_gc_all, now));
+    }
+
+    #[test]
+    fn due_for_gc_is_true_once_the_full_interval_elapses() {
+        let now = Instant::now();
+        let last_gc_all = now - Duration::from_secs(GC_ALL_INTERVAL_SECS);
+        assert!(due_for_gc(last_gc_all, now));
+    }
+
+    #[test]
+    fn next_page_cookie_is_none_once_the_last_page_is_short() {
+        assert_eq!(next_page_cookie(10, 0, 10), None);
+        assert_eq!(next_page_cookie(0, 0, 0), None);
+    }
+
+    #[test]
+    fn next_page_cookie_points_past_the_current_page_when_more_remain() {
+        assert_eq!(
+            next_page_cookie(150, 0, DISCOVER_PAGE_SIZE),
+            Some(RendezvousCookie(DISCOVER_PAGE_SIZE))
+        );
+        assert_eq!(
+            next_page_cookie(150, DISCOVER_PAGE_SIZE, DISCOVER_PAGE_SIZE),
+            Some(RendezvousCookie(2 * DISCOVER_PAGE_SIZE))
+        );
+    }
+
+    #[test]
+    fn next_page_cookie_is_none_exactly_on_the_final_page_boundary() {
+        assert_eq!(next_page_cookie(DISCOVER_PAGE_SIZE, 0, DISCOVER_PAGE_SIZE), None);
+    }
+
+    fn ip(last_octet: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, last_octet))
+    }
+
+    #[test]
+    fn decrement_ip_count_removes_the_entry_once_it_reaches_zero() {
+        let mut registrations_per_ip = HashMap::new();
+        registrations_per_ip.insert(ip(1), 1);
+        decrement_ip_count(&mut registrations_per_ip, ip(1));
+        assert_eq!(registrations_per_ip.get(&ip(1)), None);
+    }
+
+    #[test]
+    fn decrement_ip_count_leaves_the_entry_in_place_while_it_stays_above_zero() {
+        let mut registrations_per_ip = HashMap::new();
+        registrations_per_ip.insert(ip(1), 2);
+        decrement_ip_count(&mut registrations_per_ip, ip(1));
+        assert_eq!(registrations_per_ip.get(&ip(1)), Some(&1));
+    }
+
+    #[test]
+    fn decrement_ip_count_is_a_no_op_for_an_ip_with_no_entry() {
+        let mut registrations_per_ip = HashMap::new();
+        decrement_ip_count(&mut registrations_per_ip, ip(1));
+        assert!(registrations_per_ip.is_empty());
+    }
+
+    /// Reproduces the exact regression `gc_all` exists to fix: an IP registers under
+    /// `MAX_REGISTRATIONS_PER_IP` distinct namespaces and all of them expire. Decrementing only
+    /// the namespace a later request happens to touch (what `gc_namespace` alone would do)
+    /// leaves the IP pinned at its cap forever; decrementing for every namespace it expired out
+    /// of (what `gc_all`'s full sweep does) frees it back up to zero.
+    #[test]
+    fn an_ip_is_not_pinned_at_its_cap_once_every_namespace_it_registered_into_has_expired() {
+        let mut registrations_per_ip = HashMap::new();
+        registrations_per_ip.insert(ip(1), MAX_REGISTRATIONS_PER_IP);
+
+        // `gc_namespace` touching just one of the 16 namespaces: still pinned.
+        decrement_ip_count(&mut registrations_per_ip, ip(1));
+        assert_eq!(
+            registrations_per_ip.get(&ip(1)),
+            Some(&(MAX_REGISTRATIONS_PER_IP - 1))
+        );
+
+        // `gc_all` sweeping every namespace the IP expired out of: freed entirely.
+        for _ in 0..(MAX_REGISTRATIONS_PER_IP - 1) {
+            decrement_ip_count(&mut registrations_per_ip, ip(1));
+        }
+        assert_eq!(registrations_per_ip.get(&ip(1)), None);
+    }
+}